@@ -5,15 +5,23 @@
 //! Implementation of BIP-119 default template hash calculation, as defined at
 //! <https://github.com/bitcoin/bips/blob/master/bip-0119.mediawiki>
 
+use core::borrow::Borrow;
+use core::fmt;
+
 use hashes::{hash_newtype, Hash, sha256};
 use io::{BufRead, Write};
 
 use crate::{
+    absolute,
     consensus::Decodable,
     consensus::Encodable,
     consensus::Error,
     hashes::Sha256,
-    Transaction,
+    opcodes::all::OP_NOP4,
+    script::{Builder, ScriptBuf},
+    sighash::SighashCache,
+    transaction,
+    Amount, OutPoint, Sequence, Transaction, TxIn, TxOut, Witness,
 };
 
 hash_newtype! {
@@ -46,61 +54,310 @@ impl Decodable for DefaultCheckTemplateVerifyHash {
 
 const CTV_ENC_EXPECT_MSG: &str = "hash writes are infallible";
 
+/// Hash the index-independent prefix of the BIP-119 default template for `transaction`, given the
+/// single-SHA256 midstates of the serialized sequence and output vectors.
+///
+/// This is everything up to (but not including) the trailing little-endian input index: the
+/// version, locktime, the scriptSig hash (only when any input has a non-empty scriptSig), the
+/// input count and sequence hash, and the output count and output hash. Writing the input index
+/// and finalizing the returned engine yields the [`DefaultCheckTemplateVerifyHash`].
+fn default_template_prefix_with(
+    transaction: &Transaction,
+    sequences: Sha256,
+    outputs: Sha256,
+) -> sha256::HashEngine {
+    // Since Sha256::write() won't fail and consensus_encode() guarantees to never
+    // fail unless the underlying Write::write() fails, we don't need to worry about
+    // fallibility
+    let mut sha256 = Sha256::engine();
+
+    transaction.version.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+    transaction.lock_time.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+
+    let any_script_sigs = transaction.input.iter()
+        .any(|input| !input.script_sig.is_empty());
+
+    if any_script_sigs {
+        let mut script_sig_sha256 = Sha256::engine();
+
+        for input in transaction.input.iter() {
+            input.script_sig.consensus_encode(&mut script_sig_sha256).expect(CTV_ENC_EXPECT_MSG);
+        }
+
+        let script_sig_sha256 = Sha256::from_engine(script_sig_sha256);
+        script_sig_sha256.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+    }
+
+    let vin_count: u32 = transaction.input.len() as u32;
+    sha256.write(&vin_count.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+    sequences.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+
+    let vout_count: u32 = transaction.output.len() as u32;
+    sha256.write(&vout_count.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+    outputs.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+
+    sha256
+}
+
+/// Hash the index-independent prefix, computing the sequence and output midstates from scratch.
+fn default_template_prefix(transaction: &Transaction) -> sha256::HashEngine {
+    let mut sequences_sha256 = Sha256::engine();
+    for input in transaction.input.iter() {
+        let sequence: u32 = input.sequence.to_consensus_u32();
+        sequences_sha256.write(&sequence.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+    }
+
+    let mut outputs_sha256 = Sha256::engine();
+    for output in transaction.output.iter() {
+        output.consensus_encode(&mut outputs_sha256).expect(CTV_ENC_EXPECT_MSG);
+    }
+
+    default_template_prefix_with(
+        transaction,
+        Sha256::from_engine(sequences_sha256),
+        Sha256::from_engine(outputs_sha256),
+    )
+}
+
+/// Finalize a prefix engine against `input_index`.
+fn finalize_template(mut sha256: sha256::HashEngine, input_index: u32) -> DefaultCheckTemplateVerifyHash {
+    sha256.write(&input_index.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+    DefaultCheckTemplateVerifyHash(Sha256::from_engine(sha256))
+}
+
 impl DefaultCheckTemplateVerifyHash {
     /// Calculate the BIP-119 default template for a transaction at a particular input index
+    ///
+    /// This is a convenience wrapper that builds a throwaway [`SighashCache`]; if you already hold
+    /// one for the transaction (for example because you are also producing a Taproot signature
+    /// hash) prefer [`SighashCache::ctv_default_hash`], which reuses the cached sequence and output
+    /// midstates instead of hashing those vectors again.
     pub fn new(transaction: &Transaction, input_index: u32) -> Self {
-        // Since Sha256::write() won't fail and consensus_encode() guarantees to never
-        // fail unless the underlying Write::write() fails, we don't need to worry about
-        // fallibility
-        let mut sha256 = Sha256::engine();
+        SighashCache::new(transaction).ctv_default_hash(input_index)
+    }
+}
 
-        transaction.version.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
-        transaction.lock_time.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+impl<R: Borrow<Transaction>> SighashCache<R> {
+    /// Calculate the BIP-119 default template hash for the cached transaction at `input_index`.
+    ///
+    /// BIP-341 Taproot sighashing and BIP-119 both commit to a single-SHA256 of the serialized
+    /// `nSequence` vector and a single-SHA256 of the serialized outputs (unlike BIP-143, which
+    /// double-hashes), so the two midstates already held in the cache are exactly the ones the
+    /// default template needs. Reusing them means a transaction with many outputs is not hashed
+    /// twice when the same cache also produces a Taproot signature hash.
+    pub fn ctv_default_hash(&mut self, input_index: u32) -> DefaultCheckTemplateVerifyHash {
+        let (sequences, outputs) = {
+            let common = self.common_cache();
+            (common.sequences, common.outputs)
+        };
+        let engine = default_template_prefix_with(self.transaction(), sequences, outputs);
+        finalize_template(engine, input_index)
+    }
+}
 
-        let any_script_sigs = transaction.input.iter()
-            .any(|input| !input.script_sig.is_empty());
+/// A partially-computed BIP-119 default template hash for a fixed transaction.
+///
+/// Every field of the default template commits to the whole transaction except the trailing
+/// 4-byte input index, so when the same transaction is hashed at many indices — as vault and
+/// congestion-control tree constructions do when enumerating spend paths — all of the expensive
+/// work is shared. `CtvTemplate` performs that work once and stores the partially-updated SHA256
+/// engine; [`hash_for_index`](Self::hash_for_index) then only needs to clone the midstate, write
+/// the little-endian index, and finalize. This turns hashing `N` indices from `O(N * tx_size)`
+/// into `O(tx_size + N)`.
+#[derive(Clone)]
+pub struct CtvTemplate {
+    engine: sha256::HashEngine,
+}
 
-        if any_script_sigs {
-            let mut script_sig_sha256 = Sha256::engine();
+// `sha256::HashEngine` does not implement `Debug`, so we cannot derive it; the midstate is not
+// meaningful to print anyway.
+impl fmt::Debug for CtvTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CtvTemplate").finish_non_exhaustive()
+    }
+}
 
-            for input in transaction.input.iter() {
-                input.script_sig.consensus_encode(&mut script_sig_sha256).expect(CTV_ENC_EXPECT_MSG);
-            }
+impl CtvTemplate {
+    /// Precompute the index-independent portion of the default template hash for `transaction`.
+    pub fn from_tx(transaction: &Transaction) -> Self {
+        CtvTemplate { engine: default_template_prefix(transaction) }
+    }
 
-            let script_sig_sha256 = Sha256::from_engine(script_sig_sha256);
-            script_sig_sha256.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
-        }
+    /// Finalize the default template hash for `input_index` without recomputing the shared prefix.
+    pub fn hash_for_index(&self, input_index: u32) -> DefaultCheckTemplateVerifyHash {
+        finalize_template(self.engine.clone(), input_index)
+    }
+}
+
+impl ScriptBuf {
+    /// Construct the standard BIP-119 commitment script `<32-byte hash> OP_CHECKTEMPLATEVERIFY`.
+    ///
+    /// `OP_CHECKTEMPLATEVERIFY` occupies the `OP_NOP4` code point, so on nodes that have not
+    /// activated BIP-119 this behaves as a no-op; on activated nodes it enforces that the spending
+    /// transaction matches `hash`.
+    pub fn new_p2ctv(hash: &DefaultCheckTemplateVerifyHash) -> Self {
+        Builder::new()
+            .push_slice(hash.to_byte_array())
+            .push_opcode(OP_NOP4)
+            .into_script()
+    }
+}
 
-        let vin_count: u32 = transaction.input.len() as u32;
-        sha256.write(&vin_count.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+/// Check that the default template hash of `tx` at `input_index` matches `expected`.
+///
+/// This is the verification half of the CTV round trip: given a hash committed to by a
+/// [`ScriptBuf::new_p2ctv`] output, recompute it over the spending transaction and compare.
+pub fn verify_template(
+    tx: &Transaction,
+    input_index: u32,
+    expected: &DefaultCheckTemplateVerifyHash,
+) -> bool {
+    &DefaultCheckTemplateVerifyHash::new(tx, input_index) == expected
+}
 
-        {
-            let mut sequences_sha256 = Sha256::engine();
-            for input in transaction.input.iter() {
-                let sequence: u32 = input.sequence.to_consensus_u32();
-                sequences_sha256.write(&sequence.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
-            }
-            let sequences_sha256 = Sha256::from_engine(sequences_sha256);
-            sequences_sha256.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+/// A node of a CTV congestion-control / vault tree.
+///
+/// To expand the node its [`transaction`](Self::transaction) must be broadcast; it spends the
+/// output committing to [`hash`](Self::hash) and creates the node's children (either further CTV
+/// commitments or the final payouts at the leaves).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateNode {
+    /// The default template hash the parent output commits to.
+    pub hash: DefaultCheckTemplateVerifyHash,
+    /// The transaction that expands this node when broadcast.
+    pub transaction: Transaction,
+}
+
+/// A tree of CTV templates batching many payouts behind a single on-chain UTXO.
+///
+/// Given the final `(recipient, amount)` payouts and a branching factor, the tree is built
+/// bottom-up: payouts are grouped into transactions of at most `branching_factor` outputs, each
+/// such transaction is committed to by a CTV output in its parent, and so on up to a single root.
+/// Funding the [`root`](Self::root) commitment with the parent input amount lets the whole batch
+/// be settled by broadcasting the nodes on demand.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateTree {
+    nodes: Vec<TemplateNode>,
+    root: DefaultCheckTemplateVerifyHash,
+}
+
+impl TemplateTree {
+    /// Build the template tree for `payouts`, grouping `branching_factor` children per transaction
+    /// and reserving `fee` for every expansion transaction.
+    ///
+    /// Each transaction distributes its funding input, minus `fee`, across its children, so the
+    /// amount that must fund a node equals the sum of its children plus `fee`. Returns an error if
+    /// there are no payouts, if `branching_factor` is less than two, if any amount sum overflows,
+    /// or if a transaction's output count would overflow the `u32` cast performed by the hasher.
+    pub fn new(
+        payouts: &[(ScriptBuf, Amount)],
+        branching_factor: usize,
+        fee: Amount,
+    ) -> Result<Self, TemplateTreeError> {
+        if payouts.is_empty() {
+            return Err(TemplateTreeError::NoPayouts);
         }
+        if branching_factor < 2 {
+            return Err(TemplateTreeError::InvalidBranchingFactor(branching_factor));
+        }
+        // A transaction's output count is cast to `u32` by the hasher; reject up front any
+        // branching factor that could not fit even a full group.
+        if u32::try_from(branching_factor).is_err() {
+            return Err(TemplateTreeError::OutputCountOverflow(branching_factor));
+        }
+
+        let mut nodes = Vec::new();
+        let mut level: Vec<TxOut> = payouts
+            .iter()
+            .map(|(script_pubkey, value)| TxOut { value: *value, script_pubkey: script_pubkey.clone() })
+            .collect();
 
-        let vout_count: u32 = transaction.output.len() as u32;
-        sha256.write(&vout_count.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+        loop {
+            let single = level.len() <= branching_factor;
+            let mut next = Vec::new();
+            let mut last_hash = None;
 
-        {
-            let mut outputs_sha256 = Sha256::engine();
-            for output in transaction.output.iter() {
-                output.consensus_encode(&mut outputs_sha256).expect(CTV_ENC_EXPECT_MSG);
+            for chunk in level.chunks(branching_factor) {
+                // `chunk.len() <= branching_factor`, which was already checked to fit `u32`.
+                let mut subtotal = Amount::ZERO;
+                for output in chunk {
+                    subtotal = subtotal.checked_add(output.value).ok_or(TemplateTreeError::AmountOverflow)?;
+                }
+                let input_value = subtotal.checked_add(fee).ok_or(TemplateTreeError::AmountOverflow)?;
+
+                let transaction = expansion_transaction(chunk.to_vec());
+                let hash = DefaultCheckTemplateVerifyHash::new(&transaction, 0);
+
+                next.push(TxOut { value: input_value, script_pubkey: ScriptBuf::new_p2ctv(&hash) });
+                nodes.push(TemplateNode { hash, transaction });
+                last_hash = Some(hash);
+            }
+
+            if single {
+                // The single remaining chunk is the root expansion transaction.
+                let root = last_hash.expect("at least one payout");
+                return Ok(TemplateTree { nodes, root });
             }
 
-            let outputs_sha256 = Sha256::from_engine(outputs_sha256);
-            outputs_sha256.consensus_encode(&mut sha256).expect(CTV_ENC_EXPECT_MSG);
+            level = next;
         }
+    }
+
+    /// The default template hash the funding UTXO must commit to.
+    pub fn root(&self) -> &DefaultCheckTemplateVerifyHash { &self.root }
+
+    /// The nodes of the tree, leaves first and the root last.
+    pub fn nodes(&self) -> &[TemplateNode] { &self.nodes }
+}
 
-        sha256.write(&input_index.to_le_bytes()).expect(CTV_ENC_EXPECT_MSG);
+/// Build the single-input expansion transaction carrying `outputs`.
+///
+/// The default template hash commits to the version, locktime and `nSequence` of the spending
+/// transaction, so the transaction finally broadcast to expand a node MUST reproduce the fields
+/// fixed here — version 2, locktime 0, and a single input with `nSequence` `0xffff_fffe`
+/// ([`Sequence::ENABLE_LOCKTIME_NO_RBF`]) — or the recomputed hash will not match the node's
+/// committed [`TemplateNode::hash`]. Only the input's prevout is left for the caller to fill in (the
+/// template does not commit to it), and only the scriptSig must stay empty.
+fn expansion_transaction(outputs: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
+            witness: Witness::new(),
+        }],
+        output: outputs,
+    }
+}
 
-        DefaultCheckTemplateVerifyHash(
-            Sha256::from_engine(sha256)
-        )
+/// Error constructing a [`TemplateTree`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TemplateTreeError {
+    /// No payouts were supplied.
+    NoPayouts,
+    /// The branching factor was less than two.
+    InvalidBranchingFactor(usize),
+    /// A transaction's output count would overflow the `u32` cast in the hasher.
+    OutputCountOverflow(usize),
+    /// Summing amounts overflowed [`Amount`].
+    AmountOverflow,
+}
+
+impl fmt::Display for TemplateTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateTreeError::NoPayouts => write!(f, "no payouts supplied"),
+            TemplateTreeError::InvalidBranchingFactor(n) =>
+                write!(f, "branching factor {} must be at least two", n),
+            TemplateTreeError::OutputCountOverflow(n) =>
+                write!(f, "output count {} overflows the u32 cast used by the template hasher", n),
+            TemplateTreeError::AmountOverflow => write!(f, "summing payout amounts overflowed"),
+        }
     }
 }
+
+#[cfg(feature = "std")]
+impl std::error::Error for TemplateTreeError {}
@@ -3,7 +3,11 @@
 
 #![cfg(feature = "serde")]
 
-use bitcoin::{bip119::DefaultCheckTemplateVerifyHash, Transaction};
+use bitcoin::bip119::{
+    self, CtvTemplate, DefaultCheckTemplateVerifyHash, TemplateTreeError, TemplateTree,
+};
+use bitcoin::hashes::Hash;
+use bitcoin::{Amount, ScriptBuf, Transaction};
 use serde::Deserialize;
 use std::collections::HashMap;
 
@@ -54,3 +58,91 @@ fn test_ctv_hash() {
         assert_eq!(ctv_hash, expected_ctv_hash);
     }
 }
+
+#[test]
+fn test_ctv_template_matches_new() {
+    // `CtvTemplate::hash_for_index` must agree with `DefaultCheckTemplateVerifyHash::new` for the
+    // spend index of every vector, and for a range of indices against a single transaction.
+    let mut first = None;
+    for (tx, index, _expected) in get_ctv_test_vectors() {
+        let template = CtvTemplate::from_tx(&tx);
+        assert_eq!(template.hash_for_index(index), DefaultCheckTemplateVerifyHash::new(&tx, index));
+        first.get_or_insert(tx);
+    }
+
+    let tx = first.expect("at least one test vector");
+    let template = CtvTemplate::from_tx(&tx);
+    for index in 0..8u32 {
+        assert_eq!(template.hash_for_index(index), DefaultCheckTemplateVerifyHash::new(&tx, index));
+    }
+}
+
+#[test]
+fn test_p2ctv_verify_round_trip() {
+    let (tx, index, expected) = get_ctv_test_vectors().next().expect("at least one test vector");
+
+    let hash = DefaultCheckTemplateVerifyHash::new(&tx, index);
+    assert_eq!(hash, expected);
+
+    // `<32-byte push> OP_NOP4` is 1 + 32 + 1 bytes.
+    let script = ScriptBuf::new_p2ctv(&hash);
+    assert_eq!(script.len(), 34);
+
+    assert!(bip119::verify_template(&tx, index, &hash));
+
+    let wrong = DefaultCheckTemplateVerifyHash::from_byte_array([0u8; 32]);
+    assert!(!bip119::verify_template(&tx, index, &wrong));
+}
+
+fn sample_payouts() -> Vec<(ScriptBuf, Amount)> {
+    vec![
+        (ScriptBuf::new(), Amount::from_sat(1_000)),
+        (ScriptBuf::new(), Amount::from_sat(2_000)),
+        (ScriptBuf::new(), Amount::from_sat(3_000)),
+    ]
+}
+
+#[test]
+fn test_template_tree_accounting() {
+    let payouts = sample_payouts();
+    let fee = Amount::from_sat(100);
+    let tree = TemplateTree::new(&payouts, 2, fee).expect("valid tree");
+
+    // Three payouts with branching factor two: two leaf transactions plus the root.
+    assert_eq!(tree.nodes().len(), 3);
+
+    let root_node = tree.nodes().last().unwrap();
+    assert_eq!(&root_node.hash, tree.root());
+    assert!(bip119::verify_template(&root_node.transaction, 0, tree.root()));
+
+    // The root funds each child with the child's output sum plus the per-transaction fee.
+    let leaf = &tree.nodes()[0];
+    let leaf_sum = leaf.transaction.output.iter().fold(Amount::ZERO, |acc, o| acc + o.value);
+    assert_eq!(leaf_sum, Amount::from_sat(3_000));
+
+    let root_tx = &root_node.transaction;
+    assert_eq!(root_tx.output.len(), 2);
+    assert_eq!(root_tx.output[0].value, leaf_sum + fee);
+    assert_eq!(root_tx.output[0].script_pubkey, ScriptBuf::new_p2ctv(&leaf.hash));
+}
+
+#[test]
+fn test_template_tree_errors() {
+    let payouts = sample_payouts();
+    let fee = Amount::from_sat(100);
+
+    assert_eq!(TemplateTree::new(&[], 2, fee).unwrap_err(), TemplateTreeError::NoPayouts);
+    assert_eq!(
+        TemplateTree::new(&payouts, 1, fee).unwrap_err(),
+        TemplateTreeError::InvalidBranchingFactor(1),
+    );
+
+    let overflow = vec![
+        (ScriptBuf::new(), Amount::MAX),
+        (ScriptBuf::new(), Amount::MAX),
+    ];
+    assert_eq!(
+        TemplateTree::new(&overflow, 2, Amount::ZERO).unwrap_err(),
+        TemplateTreeError::AmountOverflow,
+    );
+}
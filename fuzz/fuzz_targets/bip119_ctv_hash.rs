@@ -0,0 +1,56 @@
+use bitcoin::bip119::DefaultCheckTemplateVerifyHash;
+use bitcoin::consensus::encode;
+use bitcoin::Transaction;
+use honggfuzz::fuzz;
+
+fn do_test(data: &[u8]) {
+    // Spend the leading four bytes on the input index so the fuzzer can drive it
+    // independently of the transaction body.
+    if data.len() < 4 {
+        return;
+    }
+    let (index, tx_bytes) = data.split_at(4);
+    let input_index = u32::from_le_bytes(index.try_into().expect("four bytes"));
+
+    if let Ok(tx) = encode::deserialize::<Transaction>(tx_bytes) {
+        // The interesting property is that hashing never panics: the `expect` paths and the
+        // `input.len() as u32` / `output.len() as u32` casts are the candidates on adversarial
+        // inputs.
+        let _ = DefaultCheckTemplateVerifyHash::new(&tx, input_index);
+    }
+}
+
+fn main() {
+    loop {
+        fuzz!(|data| {
+            do_test(data);
+        });
+    }
+}
+
+#[cfg(all(test, fuzzing))]
+mod tests {
+    fn extend_vec_from_hex(hex: &str, out: &mut Vec<u8>) {
+        let mut b = 0;
+        for (idx, c) in hex.as_bytes().iter().enumerate() {
+            b <<= 4;
+            match *c {
+                b'A'..=b'F' => b |= c - b'A' + 10,
+                b'a'..=b'f' => b |= c - b'a' + 10,
+                b'0'..=b'9' => b |= c - b'0',
+                _ => panic!("Bad hex"),
+            }
+            if (idx & 1) == 1 {
+                out.push(b);
+                b = 0;
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_crash() {
+        let mut a = Vec::new();
+        extend_vec_from_hex("00000000", &mut a);
+        super::do_test(&a);
+    }
+}